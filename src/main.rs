@@ -1,26 +1,73 @@
-use std::{env, fs, os::unix::process::ExitStatusExt, process::Stdio};
+use std::{
+	env, fs,
+	io::{ErrorKind, Read, Write, stderr},
+	os::fd::AsRawFd,
+	path::Path,
+	process::Stdio,
+	time::Duration,
+};
 
 use async_openai::{
 	Client,
 	config::OpenAIConfig,
 	types::{
-		ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+		ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+		ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage, ChatCompletionTool,
 		ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageArgs,
 		ChatCompletionRequestUserMessageArgs, ChatCompletionToolArgs, ChatCompletionToolType,
-		CreateChatCompletionRequestArgs, FunctionObjectArgs,
+		CreateChatCompletionRequestArgs, FinishReason, FunctionCall, FunctionObjectArgs,
 	},
 };
 use eyre::bail;
+use futures::StreamExt;
+use portable_pty::{CommandBuilder, NativePtySystem, PtySize, PtySystem};
 use rustyline::{Config, Editor, error::ReadlineError, history::DefaultHistory};
 use serde::Deserialize;
 use serde_json::json;
-use tokio::{process::Command, spawn};
-use tracing::{trace, warn};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+	process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+	time::timeout,
+};
+use tracing::trace;
+
+/// User configuration read from `~/.config/aishell/config.toml`. Every field is
+/// optional; missing fields fall back to the defaults below (and, for the key,
+/// to the legacy `openai-api-key` file).
+#[derive(Deserialize)]
+#[serde(default)]
+struct AppConfig {
+	model: String,
+	api_base: Option<String>,
+	proxy: Option<String>,
+	max_completion_tokens: u32,
+	api_key: Option<String>,
+}
+
+impl Default for AppConfig {
+	fn default() -> Self {
+		Self {
+			model: "gpt-4.1-mini".to_string(),
+			api_base: None,
+			proxy: None,
+			max_completion_tokens: 4096,
+			api_key: None,
+		}
+	}
+}
+
+fn load_config(path: &Path) -> eyre::Result<AppConfig> {
+	match fs::read_to_string(path) {
+		Ok(contents) => Ok(toml::from_str(&contents)?),
+		Err(e) if e.kind() == ErrorKind::NotFound => Ok(AppConfig::default()),
+		Err(e) => Err(e.into()),
+	}
+}
 
 fn init_context() -> eyre::Result<Vec<ChatCompletionRequestMessage>> {
 	Ok(vec![
 		ChatCompletionRequestSystemMessageArgs::default()
-			.content("Use the exec tool to run bash commands that corresponds to the user's request. These will be run in a child process, so e.g. 'cd' will not persist outside your command. Don't hesitate to run multiple tool commands. When you've received the results, add an explanation.")
+			.content("Use the exec tool to run bash commands that correspond to the user's request. The commands run in a single persistent bash session, so state such as the working directory (`cd`), exported variables, and shell functions persists across tool calls. The exception is a command run with `interactive` set: it runs in a separate bash process on a pseudo-terminal, so it neither sees nor changes that session state. Don't hesitate to run multiple tool commands. When you've received the results, add an explanation.")
 			.build()?
 			.into(),
 	])
@@ -36,131 +83,677 @@ fn add_prompt(ctx: &mut Vec<ChatCompletionRequestMessage>, prompt: &str) -> eyre
 	Ok(())
 }
 
+/// Build a function-type tool definition from its name, description and JSON
+/// Schema parameters.
+fn function_tool(
+	name: &str,
+	description: &str,
+	parameters: serde_json::Value,
+) -> eyre::Result<ChatCompletionTool> {
+	Ok(ChatCompletionToolArgs::default()
+		.r#type(ChatCompletionToolType::Function)
+		.function(
+			FunctionObjectArgs::default()
+				.name(name)
+				.description(description)
+				.parameters(parameters)
+				.build()?,
+		)
+		.build()?)
+}
+
+/// Route a tool call to its handler, parsing the call's JSON arguments into the
+/// handler's own argument struct. The returned string becomes the tool message.
+async fn dispatch_tool(
+	name: &str,
+	arguments: &str,
+	shell: &mut Shell,
+	rl: &mut Editor<(), DefaultHistory>,
+) -> eyre::Result<String> {
+	match name {
+		"exec" => {
+			let args: ExecArgs = serde_json::from_str(arguments)?;
+			execute_bash(
+				shell,
+				rl,
+				args.command,
+				args.timeout,
+				args.dangerous,
+				args.interactive,
+			)
+			.await
+		}
+		"read_file" => read_file(serde_json::from_str(arguments)?).await,
+		"write_file" => write_file(serde_json::from_str(arguments)?).await,
+		"apply_patch" => apply_patch(serde_json::from_str(arguments)?).await,
+		other => bail!("unknown function {other}"),
+	}
+}
+
 async fn ai(
 	client: &Client<OpenAIConfig>,
 	ctx: &mut Vec<ChatCompletionRequestMessage>,
+	shell: &mut Shell,
+	config: &AppConfig,
+	rl: &mut Editor<(), DefaultHistory>,
 ) -> eyre::Result<String> {
 	let request = CreateChatCompletionRequestArgs::default()
-		.max_completion_tokens(4096u32)
-		.model("gpt-4.1-mini")
+		.max_completion_tokens(config.max_completion_tokens)
+		.model(&config.model)
 		.messages(ctx.as_slice())
 		.tools(vec![
-			ChatCompletionToolArgs::default()
-				.r#type(ChatCompletionToolType::Function)
-				.function(
-					FunctionObjectArgs::default()
-						.name("exec")
-						.description("execute a bash command")
-						.parameters(json!({
-							"type": "object",
-								"properties": {
-									"command": {
-										"type": "string",
-										"description": "The bash command to execute, e.g. `ping 127.0.0.1`",
-									},
-									"timeout": {
-										"type": "number",
-										"description": "Timeout in seconds for this bash command (default: 10 seconds)",
-									},
-									"dangerous": {
-										"type": "boolean",
-										"description": "Is this command potentially dangerous? This will require the user to manually accept the command before it will be executed.",
-									},
-								},
-								"required": ["command"],
-						}))
-						.build()?,
-				)
-				.build()?,
+			function_tool(
+				"exec",
+				"execute a bash command",
+				json!({
+					"type": "object",
+						"properties": {
+							"command": {
+								"type": "string",
+								"description": "The bash command to execute, e.g. `ping 127.0.0.1`",
+							},
+							"timeout": {
+								"type": "number",
+								"description": "Timeout in seconds for this bash command (default: 10 seconds; an interactive command has no timeout unless one is given)",
+							},
+							"dangerous": {
+								"type": "boolean",
+								"description": "Is this command potentially dangerous? This will require the user to manually accept the command before it will be executed.",
+							},
+							"interactive": {
+								"type": "boolean",
+								"description": "Run the command attached to a pseudo-terminal. Set this when the command needs a TTY, e.g. it pages output, prompts for a password (sudo), draws progress bars, or is itself interactive (top, vim).",
+							},
+						},
+						"required": ["command"],
+				}),
+			)?,
+			function_tool(
+				"read_file",
+				"read the contents of a file, optionally restricted to a line or byte range",
+				json!({
+					"type": "object",
+						"properties": {
+							"path": {
+								"type": "string",
+								"description": "Path of the file to read.",
+							},
+							"start_line": {
+								"type": "number",
+								"description": "First line to return (1-indexed, inclusive).",
+							},
+							"end_line": {
+								"type": "number",
+								"description": "Last line to return (1-indexed, inclusive).",
+							},
+							"start_byte": {
+								"type": "number",
+								"description": "First byte to return (0-indexed). Takes precedence over the line range.",
+							},
+							"end_byte": {
+								"type": "number",
+								"description": "Exclusive end byte.",
+							},
+						},
+						"required": ["path"],
+				}),
+			)?,
+			function_tool(
+				"write_file",
+				"write content to a file, creating it or (optionally) overwriting an existing one",
+				json!({
+					"type": "object",
+						"properties": {
+							"path": {
+								"type": "string",
+								"description": "Path of the file to write.",
+							},
+							"content": {
+								"type": "string",
+								"description": "The full contents to write.",
+							},
+							"overwrite": {
+								"type": "boolean",
+								"description": "Overwrite the file if it already exists (default: false, which fails on an existing file).",
+							},
+						},
+						"required": ["path", "content"],
+				}),
+			)?,
+			function_tool(
+				"apply_patch",
+				"edit a file by replacing an exact snippet of text with another; the snippet must match exactly once",
+				json!({
+					"type": "object",
+						"properties": {
+							"path": {
+								"type": "string",
+								"description": "Path of the file to edit.",
+							},
+							"search": {
+								"type": "string",
+								"description": "Exact text to find. Must match exactly once in the file.",
+							},
+							"replace": {
+								"type": "string",
+								"description": "Replacement text.",
+							},
+						},
+						"required": ["path", "search", "replace"],
+				}),
+			)?,
 		])
 		.build()?;
-	let response = client.chat().create(request).await?;
-	if response.choices.is_empty() {
-		bail!("empty response (`choices` is empty)");
-	}
-	let message = response.choices.into_iter().next().unwrap().message;
-	trace!("< {message:?}");
-	if let Some(tool_calls) = message.tool_calls {
-		let mut handles = Vec::new();
-		for call in tool_calls.iter() {
-			if call.function.name != "exec" {
-				bail!("unknown function {}", call.function.name);
-			}
-			let args: ExecArgs = serde_json::from_str(&call.function.arguments)?;
-			handles.push((
-				spawn(execute_bash(args.command, args.timeout, args.dangerous)),
-				call.clone(),
-			));
+	let mut stream = client.chat().create_stream(request).await?;
+	let mut content = String::new();
+	let mut partial_tool_calls: Vec<PartialToolCall> = Vec::new();
+	let mut finish_reason = None;
+	while let Some(chunk) = stream.next().await {
+		let Some(choice) = chunk?.choices.into_iter().next() else {
+			continue;
+		};
+		if let Some(delta) = choice.delta.content {
+			eprint!("{delta}");
+			stderr().flush()?;
+			content.push_str(&delta);
+		}
+		if let Some(calls) = choice.delta.tool_calls {
+			accumulate_tool_calls(&mut partial_tool_calls, calls);
 		}
+		if let Some(reason) = choice.finish_reason {
+			finish_reason = Some(reason);
+		}
+	}
+	eprintln!();
+	trace!("< content={content:?} finish_reason={finish_reason:?}");
+	if finish_reason == Some(FinishReason::ToolCalls) || !partial_tool_calls.is_empty() {
+		let tool_calls: Vec<ChatCompletionMessageToolCall> = partial_tool_calls
+			.into_iter()
+			.map(PartialToolCall::into_tool_call)
+			.collect();
+		let calls: Vec<_> = tool_calls
+			.iter()
+			.map(|call| {
+				(
+					call.id.clone(),
+					call.function.name.clone(),
+					call.function.arguments.clone(),
+				)
+			})
+			.collect();
 		ctx.push(
 			ChatCompletionRequestAssistantMessageArgs::default()
 				.tool_calls(tool_calls)
 				.build()?
 				.into(),
 		);
-		for (handle, call) in handles {
-			if let Ok(response_content) = handle.await {
-				ctx.push(
-					ChatCompletionRequestToolMessageArgs::default()
-						.content(response_content.unwrap_or_else(|e| format!("error: {e}")))
-						.tool_call_id(call.id)
-						.build()?
-						.into(),
-				);
-			}
+		// The persistent shell is inherently serial, so run the requested
+		// tools one after another rather than spawning them concurrently.
+		for (id, name, arguments) in calls {
+			let response_content = dispatch_tool(&name, &arguments, shell, rl)
+				.await
+				.unwrap_or_else(|e| format!("error: {e}"));
+			ctx.push(
+				ChatCompletionRequestToolMessageArgs::default()
+					.content(response_content)
+					.tool_call_id(id)
+					.build()?
+					.into(),
+			);
 		}
-		return Box::pin(ai(client, ctx)).await;
+		return Box::pin(ai(client, ctx, shell, config, rl)).await;
 	}
-	let Some(content) = message.content else {
-		bail!("no content in response message");
-	};
 	Ok(content)
 }
 
+/// A tool call being reassembled from streamed chunks. The first chunk for a
+/// given `index` carries the `id` and function `name`; later chunks append
+/// fragments to `arguments`.
+#[derive(Default)]
+struct PartialToolCall {
+	id: String,
+	name: String,
+	arguments: String,
+}
+
+impl PartialToolCall {
+	fn into_tool_call(self) -> ChatCompletionMessageToolCall {
+		ChatCompletionMessageToolCall {
+			id: self.id,
+			r#type: ChatCompletionToolType::Function,
+			function: FunctionCall {
+				name: self.name,
+				arguments: self.arguments,
+			},
+		}
+	}
+}
+
+fn accumulate_tool_calls(
+	acc: &mut Vec<PartialToolCall>,
+	chunks: Vec<ChatCompletionMessageToolCallChunk>,
+) {
+	for chunk in chunks {
+		let index = chunk.index as usize;
+		if acc.len() <= index {
+			acc.resize_with(index + 1, PartialToolCall::default);
+		}
+		let call = &mut acc[index];
+		if let Some(id) = chunk.id {
+			call.id = id;
+		}
+		if let Some(function) = chunk.function {
+			if let Some(name) = function.name {
+				call.name = name;
+			}
+			if let Some(arguments) = function.arguments {
+				call.arguments.push_str(&arguments);
+			}
+		}
+	}
+}
+
 #[derive(Deserialize)]
 struct ExecArgs {
 	command: String,
 	timeout: Option<f64>,
 	#[serde(default)]
 	dangerous: bool,
+	#[serde(default)]
+	interactive: bool,
+}
+
+#[derive(Deserialize)]
+struct ReadFileArgs {
+	path: String,
+	start_line: Option<usize>,
+	end_line: Option<usize>,
+	start_byte: Option<usize>,
+	end_byte: Option<usize>,
+}
+
+/// Return the contents of a file, optionally restricted to a byte range (which
+/// takes precedence) or an inclusive 1-indexed line range.
+async fn read_file(args: ReadFileArgs) -> eyre::Result<String> {
+	let bytes = tokio::fs::read(&args.path).await?;
+	if args.start_byte.is_some() || args.end_byte.is_some() {
+		let end = args.end_byte.unwrap_or(bytes.len()).min(bytes.len());
+		let start = args.start_byte.unwrap_or(0).min(end);
+		return Ok(String::from_utf8_lossy(&bytes[start..end]).into_owned());
+	}
+	let text = String::from_utf8_lossy(&bytes);
+	if args.start_line.is_none() && args.end_line.is_none() {
+		return Ok(text.into_owned());
+	}
+	let start = args.start_line.unwrap_or(1).max(1);
+	let end = args.end_line.unwrap_or(usize::MAX);
+	Ok(text
+		.lines()
+		.enumerate()
+		.filter(|(i, _)| (start..=end).contains(&(i + 1)))
+		.map(|(_, line)| line)
+		.collect::<Vec<_>>()
+		.join("\n"))
+}
+
+#[derive(Deserialize)]
+struct WriteFileArgs {
+	path: String,
+	content: String,
+	#[serde(default)]
+	overwrite: bool,
+}
+
+/// Write `content` to a file, refusing to clobber an existing one unless
+/// `overwrite` is set.
+async fn write_file(args: WriteFileArgs) -> eyre::Result<String> {
+	if !args.overwrite && tokio::fs::try_exists(&args.path).await? {
+		bail!("{} already exists (set overwrite to replace it)", args.path);
+	}
+	tokio::fs::write(&args.path, &args.content).await?;
+	Ok(format!("wrote {} bytes to {}", args.content.len(), args.path))
+}
+
+#[derive(Deserialize)]
+struct ApplyPatchArgs {
+	path: String,
+	search: String,
+	replace: String,
+}
+
+/// Replace an exact snippet of text in a file. The snippet must match exactly
+/// once, so the edit is unambiguous.
+async fn apply_patch(args: ApplyPatchArgs) -> eyre::Result<String> {
+	let contents = tokio::fs::read_to_string(&args.path).await?;
+	match contents.matches(&args.search).count() {
+		0 => bail!("search text not found in {}", args.path),
+		1 => {}
+		n => bail!("search text matches {n} times in {}; make it unique", args.path),
+	}
+	let updated = contents.replacen(&args.search, &args.replace, 1);
+	tokio::fs::write(&args.path, &updated).await?;
+	Ok(format!("patched {}", args.path))
 }
 
 async fn execute_bash(
-	command: String,
+	shell: &mut Shell,
+	rl: &mut Editor<(), DefaultHistory>,
+	mut command: String,
 	timeout: Option<f64>,
 	dangerous: bool,
+	interactive: bool,
 ) -> eyre::Result<String> {
+	// A self-flagged dangerous command is gated behind an explicit confirmation
+	// rather than refused outright, so the model can still complete risky tasks
+	// with the user in the loop.
 	if dangerous {
-		bail!("not executing dangerous command {command:?}");
-	}
-	if let Some(timeout) = timeout {
-		warn!("timeout: {timeout} seconds");
+		eprintln!("the model flagged this command as dangerous:");
+		eprintln!("  {command}");
+		match rl.readline("run? [y/N/edit] ")?.trim() {
+			"y" | "Y" | "yes" => {}
+			"e" | "edit" => {
+				command = rl.readline_with_initial("edit: ", (&command, ""))?;
+			}
+			_ => return Ok("user rejected command".to_string()),
+		}
 	}
 	eprintln!(":: {command}");
-	let mut cmd = Command::new("/usr/bin/env");
-	cmd.arg("bash")
-		.arg("-c")
-		.arg(command)
-		.stdout(Stdio::piped())
-		.stderr(Stdio::piped());
-	let child = cmd.spawn()?;
-	let result = child.wait_with_output().await?;
-	print!("{}", String::from_utf8_lossy(&result.stdout));
-	eprint!("{}", String::from_utf8_lossy(&result.stderr));
+	let output = if interactive {
+		// The PTY path streams the child's output straight to the user's
+		// terminal, so there is nothing to print here afterwards.
+		execute_pty(command, timeout).await?
+	} else {
+		let output = shell.run(&command, timeout).await?;
+		print!("{}", output.stdout);
+		eprint!("{}", output.stderr);
+		output
+	};
 	Ok(serde_json::to_string(&json!({
-		"exit_code": result.status.into_raw(),
-		"stdout": String::from_utf8_lossy(&result.stdout),
-		"stderr": String::from_utf8_lossy(&result.stderr),
+		"exit_code": output.exit_code,
+		"stdout": output.stdout,
+		"stderr": output.stderr,
 	}))?)
 }
 
+/// Run a command attached to a pseudo-terminal, forwarding the child's combined
+/// output to the user's terminal and the user's keystrokes to the child, while
+/// also capturing the output for the tool result.
+///
+/// The `portable-pty` API is blocking, so the whole thing runs on a blocking
+/// thread; the child is killed if it outlives `timeout`. Unlike [`Shell::run`],
+/// an omitted `timeout` is left unbounded rather than falling back to
+/// [`DEFAULT_TIMEOUT_SECS`]: the whole point of the PTY path is long-lived
+/// interactive programs (`vim`, `top`, a `sudo` prompt) that the short
+/// `exec` default would otherwise kill mid-session.
+async fn execute_pty(command: String, timeout_secs: Option<f64>) -> eyre::Result<CommandOutput> {
+	tokio::task::spawn_blocking(move || run_pty(&command, timeout_secs)).await?
+}
+
+fn run_pty(command: &str, timeout_secs: Option<f64>) -> eyre::Result<CommandOutput> {
+	let pty = NativePtySystem::default();
+	let pair = pty.openpty(PtySize {
+		rows: 24,
+		cols: 80,
+		pixel_width: 0,
+		pixel_height: 0,
+	})?;
+	let mut cmd = CommandBuilder::new("/usr/bin/env");
+	cmd.arg("bash");
+	cmd.arg("-c");
+	cmd.arg(command);
+	let mut child = pair.slave.spawn_command(cmd)?;
+	// Only the child needs the slave end; drop ours so the master sees EOF once
+	// the child exits.
+	drop(pair.slave);
+
+	let mut reader = pair.master.try_clone_reader()?;
+	let writer = pair.master.take_writer()?;
+
+	// Kill the child if it outlives the timeout (e.g. a hung interactive prompt).
+	// With no timeout, the PTY session runs until the command itself exits.
+	if let Some(timeout_secs) = timeout_secs {
+		let mut killer = child.clone_killer();
+		std::thread::spawn(move || {
+			std::thread::sleep(Duration::from_secs_f64(timeout_secs));
+			let _ = killer.kill();
+		});
+	}
+
+	// Forward the user's keystrokes to the child until it exits. Reads are
+	// non-blocking (not a plain blocking `stdin.read()`) and polled against
+	// `done`, so the thread actually stops once the command finishes instead
+	// of staying parked on stdin forever and stealing the REPL's next line.
+	let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+	let forwarder = std::thread::spawn({
+		let done = std::sync::Arc::clone(&done);
+		move || forward_stdin(writer, &done)
+	});
+
+	// Forward and capture the child's combined output until the PTY closes.
+	let mut captured = Vec::new();
+	let mut stdout = std::io::stdout();
+	let mut buf = [0u8; 4096];
+	while let Ok(n) = reader.read(&mut buf) {
+		if n == 0 {
+			break;
+		}
+		let _ = stdout.write_all(&buf[..n]);
+		let _ = stdout.flush();
+		captured.extend_from_slice(&buf[..n]);
+	}
+
+	done.store(true, std::sync::atomic::Ordering::Relaxed);
+	let _ = forwarder.join();
+
+	let status = child.wait()?;
+	Ok(CommandOutput {
+		exit_code: status.exit_code() as i32,
+		stdout: String::from_utf8_lossy(&captured).into_owned(),
+		stderr: String::new(),
+	})
+}
+
+/// Copy bytes from stdin to `writer` until `done` is set, polling with short
+/// non-blocking reads rather than one blocking `read()` so the loop notices
+/// `done` promptly instead of sitting parked on stdin indefinitely (which
+/// would otherwise steal the REPL's next line out from under rustyline).
+fn forward_stdin(mut writer: Box<dyn Write + Send>, done: &std::sync::Arc<std::sync::atomic::AtomicBool>) {
+	use std::sync::atomic::Ordering;
+
+	let stdin = std::io::stdin();
+	let fd = stdin.as_raw_fd();
+	let Ok(orig_flags) = set_nonblocking(fd) else {
+		return;
+	};
+
+	let mut stdin = stdin.lock();
+	let mut buf = [0u8; 1024];
+	while !done.load(Ordering::Relaxed) {
+		match stdin.read(&mut buf) {
+			Ok(0) => break,
+			Ok(n) => {
+				if writer.write_all(&buf[..n]).is_err() {
+					break;
+				}
+				let _ = writer.flush();
+			}
+			Err(e) if e.kind() == ErrorKind::WouldBlock => {
+				std::thread::sleep(Duration::from_millis(20));
+			}
+			Err(_) => break,
+		}
+	}
+
+	restore_flags(fd, orig_flags);
+}
+
+/// Put `fd` into non-blocking mode, returning its previous flags so they can
+/// be restored with [`restore_flags`].
+fn set_nonblocking(fd: std::os::fd::RawFd) -> std::io::Result<libc::c_int> {
+	unsafe {
+		let flags = libc::fcntl(fd, libc::F_GETFL, 0);
+		if flags < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(flags)
+	}
+}
+
+/// Restore flags previously returned by [`set_nonblocking`].
+fn restore_flags(fd: std::os::fd::RawFd, flags: libc::c_int) {
+	unsafe {
+		libc::fcntl(fd, libc::F_SETFL, flags);
+	}
+}
+
+/// Default timeout applied to a command that does not specify one, matching the
+/// `exec` tool's documented default.
+const DEFAULT_TIMEOUT_SECS: f64 = 10.0;
+
+/// The outcome of running a command, either in the persistent [`Shell`] or on a
+/// pseudo-terminal (where stdout holds the combined output and stderr is empty).
+struct CommandOutput {
+	exit_code: i32,
+	stdout: String,
+	stderr: String,
+}
+
+/// A long-lived `bash` process whose stdin/stdout/stderr stay open for the
+/// lifetime of the REPL, so that `cd`, exported variables and other shell state
+/// persist across successive `exec` tool calls.
+struct Shell {
+	stdin: ChildStdin,
+	stdout: BufReader<ChildStdout>,
+	stderr: BufReader<ChildStderr>,
+	nonce: u64,
+	_child: Child,
+}
+
+impl Shell {
+	/// Spawn the backing `bash` process with all three standard streams piped.
+	fn spawn() -> eyre::Result<Self> {
+		let mut child = Command::new("/usr/bin/env")
+			.arg("bash")
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn()?;
+		let stdin = child.stdin.take().unwrap();
+		let stdout = BufReader::new(child.stdout.take().unwrap());
+		let stderr = BufReader::new(child.stderr.take().unwrap());
+		Ok(Self {
+			stdin,
+			stdout,
+			stderr,
+			nonce: 0,
+			_child: child,
+		})
+	}
+
+	/// Run a command to completion, returning its captured output and exit code.
+	///
+	/// A unique sentinel is echoed on both stdout and stderr after the command
+	/// so we know when its output has drained and can recover `$?`. Output is
+	/// buffered as raw bytes until the sentinel line is seen, so binary or
+	/// partial-UTF8 output is never split mid-sequence. A command that never
+	/// emits the sentinel (e.g. a hung process) is bounded by `timeout`.
+	async fn run(&mut self, command: &str, timeout_secs: Option<f64>) -> eyre::Result<CommandOutput> {
+		self.nonce += 1;
+		let sentinel = format!("__AISHELL_DONE_{}__", self.nonce);
+		// Capture `$?` before the sentinel echoes clobber it, then emit the
+		// sentinel (with the exit code) on both streams.
+		let script = format!(
+			"{command}\n__aishell_rc=$?\nprintf '%s %s\\n' {sentinel} \"$__aishell_rc\" 1>&2\nprintf '%s %s\\n' {sentinel} \"$__aishell_rc\"\n"
+		);
+		self.stdin.write_all(script.as_bytes()).await?;
+		self.stdin.flush().await?;
+
+		let stdout = &mut self.stdout;
+		let stderr = &mut self.stderr;
+		let collect = async {
+			tokio::join!(
+				read_until_sentinel(stdout, &sentinel),
+				read_until_sentinel(stderr, &sentinel),
+			)
+		};
+		let secs = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
+		let (stdout_res, stderr_res) = match timeout(Duration::from_secs_f64(secs), collect).await {
+			Ok(result) => result,
+			Err(_) => {
+				// The command is still running (or hung) with our sentinel never
+				// consumed, so the session can't be trusted for the next call:
+				// respawn a fresh `bash` rather than leaving a poisoned one behind.
+				*self = Shell::spawn()?;
+				bail!("command timed out after {secs} seconds; shell session was reset");
+			}
+		};
+		let (stdout_bytes, exit_code) = stdout_res?;
+		let (stderr_bytes, _) = stderr_res?;
+		Ok(CommandOutput {
+			exit_code: exit_code.unwrap_or(-1),
+			stdout: String::from_utf8_lossy(&stdout_bytes).into_owned(),
+			stderr: String::from_utf8_lossy(&stderr_bytes).into_owned(),
+		})
+	}
+
+	/// The session's current working directory, for display in the REPL prompt.
+	async fn pwd(&mut self) -> eyre::Result<String> {
+		Ok(self.run("pwd", None).await?.stdout.trim().to_string())
+	}
+}
+
+/// Read from `reader` one line at a time, buffering raw bytes until a line
+/// containing `sentinel` is seen. Returns the captured bytes (everything
+/// before the sentinel, across all lines) and the exit code parsed from the
+/// sentinel, if present.
+///
+/// The sentinel is matched as a substring rather than requiring it to start
+/// its own line: a command whose output doesn't end in a newline (e.g.
+/// `echo -n hi`) has the sentinel glued onto its last line, and a
+/// prefix-only match would never see it and time out waiting for a newline
+/// that's never coming.
+async fn read_until_sentinel(
+	reader: &mut (impl AsyncBufReadExt + Unpin),
+	sentinel: &str,
+) -> eyre::Result<(Vec<u8>, Option<i32>)> {
+	let mut captured = Vec::new();
+	let mut line = Vec::new();
+	loop {
+		line.clear();
+		if reader.read_until(b'\n', &mut line).await? == 0 {
+			bail!("shell session closed unexpectedly");
+		}
+		// Search the raw bytes, not a lossy-decoded copy: invalid UTF-8 earlier
+		// on the line would expand under `from_utf8_lossy` (each bad byte
+		// becomes a 3-byte U+FFFD), so an index found in the decoded string
+		// can fall past the end of `line` and panic when used to slice it.
+		if let Some(idx) = find_subslice(&line, sentinel.as_bytes()) {
+			captured.extend_from_slice(&line[..idx]);
+			let rest = String::from_utf8_lossy(&line[idx + sentinel.len()..]);
+			let rest = rest.trim_end_matches(['\n', '\r']).trim();
+			return Ok((captured, rest.parse().ok()));
+		}
+		captured.extend_from_slice(&line);
+	}
+}
+
+/// The offset of the first occurrence of `needle` in `haystack`, if any.
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|window| window == needle)
+}
+
 async fn handle_command(
 	client: &Client<OpenAIConfig>,
 	ctx: &mut Vec<ChatCompletionRequestMessage>,
 	rl: &mut Editor<(), DefaultHistory>,
+	shell: &mut Shell,
+	config: &AppConfig,
 ) -> eyre::Result<()> {
-	let pwd = env::current_dir()?;
-	let prompt = format!("{} ", pwd.display());
+	let pwd = shell.pwd().await.unwrap_or_else(|_| "?".to_string());
+	let prompt = format!("{pwd} ");
 	let line = rl.readline(&prompt)?;
 	if line.trim() == "clear" {
 		*ctx = init_context()?;
@@ -168,8 +761,9 @@ async fn handle_command(
 		return Ok(());
 	}
 	add_prompt(ctx, &line)?;
-	let response = ai(client, ctx).await?;
-	eprintln!("{response}");
+	// `ai` streams the assistant's reply to stderr as it arrives, so there is
+	// nothing left to print here.
+	ai(client, ctx, shell, config, rl).await?;
 	Ok(())
 }
 
@@ -179,18 +773,38 @@ async fn main() -> eyre::Result<()> {
 	let Some(home_dir) = env::home_dir() else {
 		bail!("couldn't determine home directory");
 	};
-	let api_key_path = home_dir.join(".config/aishell/openai-api-key");
-	fs::create_dir_all(&api_key_path.parent().unwrap())?;
-	let api_key = match fs::read_to_string(&api_key_path) {
-		Ok(key) => key.trim().to_string(),
-		Err(e) => {
-			bail!(
-				"failed to open OpenAI API key in {}: {e}",
-				api_key_path.display()
-			);
+	let config_dir = home_dir.join(".config/aishell");
+	fs::create_dir_all(&config_dir)?;
+	let config = load_config(&config_dir.join("config.toml"))?;
+	let api_key = match config.api_key.clone() {
+		Some(key) => key.trim().to_string(),
+		None => {
+			let api_key_path = config_dir.join("openai-api-key");
+			match fs::read_to_string(&api_key_path) {
+				Ok(key) => key.trim().to_string(),
+				Err(e) => {
+					bail!(
+						"failed to open OpenAI API key in {}: {e}",
+						api_key_path.display()
+					);
+				}
+			}
+		}
+	};
+	let mut openai_config = OpenAIConfig::new().with_api_key(api_key);
+	if let Some(api_base) = &config.api_base {
+		openai_config = openai_config.with_api_base(api_base);
+	}
+	let client = match &config.proxy {
+		Some(proxy) => {
+			let http = reqwest::Client::builder()
+				.proxy(reqwest::Proxy::all(proxy)?)
+				.build()?;
+			Client::with_config(openai_config).with_http_client(http)
 		}
+		None => Client::with_config(openai_config),
 	};
-	let client = Client::with_config(OpenAIConfig::new().with_api_key(api_key));
+	let mut shell = Shell::spawn()?;
 	let mut ctx = init_context()?;
 	let mut rl = rustyline::Editor::with_config(
 		Config::builder()
@@ -203,7 +817,7 @@ async fn main() -> eyre::Result<()> {
 	fs::create_dir_all(&history_path.parent().unwrap())?;
 	let _ = rl.load_history(&history_path);
 	loop {
-		let outcome = handle_command(&client, &mut ctx, &mut rl).await;
+		let outcome = handle_command(&client, &mut ctx, &mut rl, &mut shell, &config).await;
 		rl.save_history(&history_path)?;
 		match outcome {
 			Ok(()) => (),